@@ -1,17 +1,195 @@
+//! Relies on the crate-level `#![cfg_attr(not(feature = "std"), no_std)]` switch;
+//! with the default `std` feature disabled, `Vec`/`String`/`Box` come from `alloc`
+//! and `Ipv4Addr` from `core::net` instead of `std::net`.
+
+#[cfg(not(feature = "std"))]
+extern crate alloc;
+
+#[cfg(feature = "std")]
 use std::net::Ipv4Addr;
+#[cfg(not(feature = "std"))]
+use core::net::Ipv4Addr;
+
+#[cfg(feature = "std")]
+use std::vec::Vec;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
+use std::string::{String, ToString};
+#[cfg(not(feature = "std"))]
+use alloc::string::{String, ToString};
 
+#[cfg(feature = "std")]
+use std::boxed::Box;
+#[cfg(not(feature = "std"))]
+use alloc::boxed::Box;
+
+use byteorder::{BigEndian, ByteOrder};
+#[cfg(feature = "std")]
 use rustc_serialize::hex::ToHex;
-use openssl::crypto::hash::{Hasher, Type};
 
-use utils::{current_timestamp, integer_to_bytes};
+use utils::integer_to_bytes;
+#[cfg(feature = "std")]
+use utils::current_timestamp;
+
+/// Supplies the current time to the keepalive chain without requiring `std`.
+/// `SystemClock` is the ambient-time default; `no_std` callers inject their
+/// own implementation (e.g. backed by a hardware RTC).
+pub trait Clock {
+    fn now(&self) -> u32;
+}
+
+#[cfg(feature = "std")]
+pub struct SystemClock;
+
+#[cfg(feature = "std")]
+impl Clock for SystemClock {
+    fn now(&self) -> u32 {
+        current_timestamp()
+    }
+}
+
+/// Lower-case hex encoding for the MD5 digest. `rustc_serialize`'s `ToHex` needs
+/// `std`, so `no_std` builds get a small `core`-only encoder instead.
+#[cfg(feature = "std")]
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.to_hex()
+}
+
+#[cfg(not(feature = "std"))]
+fn hex_encode(bytes: &[u8]) -> String {
+    const HEX_CHARS: &[u8; 16] = b"0123456789abcdef";
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        hex.push(HEX_CHARS[(byte >> 4) as usize] as char);
+        hex.push(HEX_CHARS[(byte & 0xf) as usize] as char);
+    }
+    hex
+}
+
+/// A minimal hashing interface so `Attribute` can route its keepalive chain
+/// through whichever crypto backend is compiled in, instead of depending on
+/// a single hashing library directly.
+pub trait Digest {
+    fn update(&mut self, data: &[u8]);
+    fn finish(self) -> Vec<u8>;
+}
+
+#[cfg(feature = "crypto-openssl")]
+mod digest_impl {
+    use openssl::crypto::hash::{Hasher, Type};
+
+    use super::{Digest, Vec};
+
+    pub struct Md5(Hasher);
 
-#[derive(Debug)]
+    impl Md5 {
+        pub fn new() -> Self {
+            Md5(Hasher::new(Type::MD5).unwrap())
+        }
+    }
+
+    impl Digest for Md5 {
+        fn update(&mut self, data: &[u8]) {
+            self.0.update(data).unwrap();
+        }
+
+        fn finish(self) -> Vec<u8> {
+            self.0.finish().unwrap()
+        }
+    }
+}
+
+#[cfg(feature = "crypto-rustcrypto")]
+mod digest_impl {
+    use md5::{Digest as Md5Digest, Md5 as RustCryptoMd5};
+
+    use super::{Digest, Vec};
+
+    pub struct Md5(RustCryptoMd5);
+
+    impl Md5 {
+        pub fn new() -> Self {
+            Md5(RustCryptoMd5::new())
+        }
+    }
+
+    impl Digest for Md5 {
+        fn update(&mut self, data: &[u8]) {
+            Md5Digest::update(&mut self.0, data);
+        }
+
+        fn finish(self) -> Vec<u8> {
+            self.0.finalize().to_vec()
+        }
+    }
+}
+
+#[cfg(all(feature = "crypto-openssl", feature = "crypto-rustcrypto"))]
+compile_error!("enable exactly one of the `crypto-openssl`/`crypto-rustcrypto` features, not \
+                 both");
+
+#[cfg(not(any(feature = "crypto-openssl", feature = "crypto-rustcrypto")))]
+compile_error!("enable exactly one of the `crypto-openssl`/`crypto-rustcrypto` features to \
+                 select a keepalive hashing backend");
+
+use self::digest_impl::Md5;
+
+/// The value carried by an `Attribute`, tagged by the same variants as the
+/// wire's `value_type_id` (integer `0x0`, IPv4 `0x1`, string/raw `0x2`). Building
+/// attributes from this enum instead of a raw `(value_type_id, Vec<u8>)` pair
+/// makes a mismatched type unrepresentable.
+#[derive(Debug, PartialEq)]
+pub enum AttributeValue {
+    Integer(u32),
+    Ipv4(Ipv4Addr),
+    Bytes(Vec<u8>),
+    Text(String),
+}
+
+impl AttributeValue {
+    fn to_bytes(&self) -> Vec<u8> {
+        match *self {
+            AttributeValue::Integer(value) => {
+                let mut bytes = [0u8; 4];
+                BigEndian::write_u32(&mut bytes, value);
+                bytes.to_vec()
+            }
+            AttributeValue::Ipv4(address) => address.octets().to_vec(),
+            AttributeValue::Bytes(ref data) => data.clone(),
+            AttributeValue::Text(ref text) => text.as_bytes().to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub struct Attribute {
     typename: String,
     parent_id: u8,
     type_id: u8,
-    value_type_id: u8,
-    data: Vec<u8>,
+    value: AttributeValue,
+}
+
+/// Errors produced while decoding a byte stream back into `Attribute`s.
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    /// The buffer ended before a full attribute (or its declared data) could be read.
+    Truncated,
+    /// The declared length field is smaller than the 3-byte attribute header, or
+    /// doesn't match the fixed width expected for the decoded `AttributeKind`.
+    InvalidLength,
+    /// A `Text` attribute's data was not valid UTF-8.
+    InvalidUtf8,
+}
+
+/// What kind of `AttributeValue` a known `parent_id` decodes to, so
+/// `Attribute::from_bytes` can recover a typed value instead of raw bytes.
+enum AttributeKind {
+    Integer,
+    Ipv4,
+    Bytes,
+    Text,
 }
 
 pub trait AttributeFactory {
@@ -28,31 +206,29 @@ pub trait AttributeFactory {
     fn keepalive_data(data: &str) -> Attribute;
     fn keepalive_time(timestamp: u32) -> Attribute;
 
+    /// When `timestamp` is `None`, the current time is read via `SystemClock`,
+    /// which requires the `std` feature; `no_std` callers must always pass
+    /// `Some(timestamp)` (e.g. from their own `Clock` implementation).
     fn calc_keepalive_data(timestamp: Option<u32>, last_data: Option<&str>) -> String;
 }
 
 pub trait AttributeVec {
     fn as_bytes(&self) -> Vec<u8>;
+    fn parse_all(bytes: &[u8]) -> Result<Vec<Attribute>, ParseError>;
 }
 
 impl Attribute {
-    pub fn new(typename: &str,
-               parent_id: u8,
-               type_id: u8,
-               value_type_id: u8,
-               data: Vec<u8>)
-               -> Self {
+    pub fn new(typename: &str, parent_id: u8, type_id: u8, value: AttributeValue) -> Self {
         Attribute {
             typename: typename.to_string(),
             parent_id: parent_id,
             type_id: type_id,
-            value_type_id: value_type_id,
-            data: data,
+            value: value,
         }
     }
 
     fn data_length(&self) -> u16 {
-        self.data.len() as u16
+        self.value.to_bytes().len() as u16
     }
 
     pub fn length(&self) -> u16 {
@@ -66,85 +242,146 @@ impl Attribute {
             let length_bytes = integer_to_bytes(&length_be);
             attribute_bytes.push(self.parent_id);
             attribute_bytes.extend(length_bytes);
-            attribute_bytes.extend(&self.data);
+            attribute_bytes.extend(self.value.to_bytes());
         }
         attribute_bytes
     }
+
+    /// Parses a single attribute off the front of `bytes`, recovering the
+    /// `typename`/`type_id`/`AttributeValue` for known `parent_id`s and
+    /// falling back to raw `Bytes` under an `Unknown` typename otherwise. Use
+    /// `Attribute::length()` on the result (or `Vec<Attribute>::parse_all`) to
+    /// find where it ends.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Attribute, ParseError> {
+        if bytes.len() < 3 {
+            return Err(ParseError::Truncated);
+        }
+
+        let parent_id = bytes[0];
+        let length = BigEndian::read_u16(&bytes[1..3]);
+        if length < 3 {
+            return Err(ParseError::InvalidLength);
+        }
+
+        let data_end = 3 + (length - 3) as usize;
+        if bytes.len() < data_end {
+            return Err(ParseError::Truncated);
+        }
+        let data = &bytes[3..data_end];
+
+        let (typename, type_id, kind) = Attribute::lookup_type(parent_id);
+        let value = match kind {
+            AttributeKind::Integer => {
+                if data.len() != 4 {
+                    return Err(ParseError::InvalidLength);
+                }
+                AttributeValue::Integer(BigEndian::read_u32(data))
+            }
+            AttributeKind::Ipv4 => {
+                if data.len() != 4 {
+                    return Err(ParseError::InvalidLength);
+                }
+                AttributeValue::Ipv4(Ipv4Addr::new(data[0], data[1], data[2], data[3]))
+            }
+            AttributeKind::Text => {
+                match String::from_utf8(data.to_vec()) {
+                    Ok(text) => AttributeValue::Text(text),
+                    Err(_) => return Err(ParseError::InvalidUtf8),
+                }
+            }
+            AttributeKind::Bytes => AttributeValue::Bytes(data.to_vec()),
+        };
+
+        Ok(Attribute::new(typename, parent_id, type_id, value))
+    }
+
+    /// Maps a `parent_id` back to the `typename`/`type_id`/`AttributeKind`
+    /// used by the `AttributeFactory` constructors, for attributes recovered
+    /// from the wire. Unrecognized ids decode as raw `Bytes` under `Unknown`.
+    fn lookup_type(parent_id: u8) -> (&'static str, u8, AttributeKind) {
+        match parent_id {
+            0x1 => ("User-Name", 0x0, AttributeKind::Text),
+            0x2 => ("Client-IP-Address", 0x0, AttributeKind::Ipv4),
+            0x3 => ("Client-Version", 0x0, AttributeKind::Text),
+            0x4 => ("Client-Type", 0x0, AttributeKind::Text),
+            0x5 => ("OS-Version", 0x0, AttributeKind::Text),
+            0x6 => ("OS-Lang", 0x0, AttributeKind::Text),
+            0x8 => ("CPU-Info", 0x0, AttributeKind::Text),
+            0x9 => ("MAC-Address", 0x0, AttributeKind::Bytes),
+            0xa => ("Memory-Size", 0x0, AttributeKind::Integer),
+            0xb => ("Default-Explorer", 0x0, AttributeKind::Text),
+            0x12 => ("KeepAlive-Time", 0x0, AttributeKind::Integer),
+            0x14 => ("KeepAlive-Data", 0x0, AttributeKind::Text),
+            _ => ("Unknown", 0x0, AttributeKind::Bytes),
+        }
+    }
 }
 
 impl AttributeFactory for Attribute {
     fn username(username: &str) -> Attribute {
-        Attribute::new("User-Name", 0x1, 0x0, 0x2, username.as_bytes().to_vec())
+        Attribute::new("User-Name", 0x1, 0x0, AttributeValue::Text(username.to_string()))
     }
 
     fn client_ip_address(ipaddress: Ipv4Addr) -> Attribute {
-        Attribute::new("Client-IP-Address",
-                       0x2,
-                       0x0,
-                       0x1,
-                       ipaddress.octets().to_vec())
+        Attribute::new("Client-IP-Address", 0x2, 0x0, AttributeValue::Ipv4(ipaddress))
     }
 
     fn client_type(client_type: &str) -> Attribute {
-        Attribute::new("Client-Type",
-                       0x4,
-                       0x0,
-                       0x2,
-                       client_type.as_bytes().to_vec())
+        Attribute::new("Client-Type", 0x4, 0x0, AttributeValue::Text(client_type.to_string()))
     }
 
     fn client_version(client_version: &str) -> Attribute {
         Attribute::new("Client-Version",
                        0x3,
                        0x0,
-                       0x2,
-                       client_version.as_bytes().to_vec())
+                       AttributeValue::Text(client_version.to_string()))
     }
 
     fn os_version(version: &str) -> Attribute {
-        Attribute::new("OS-Version", 0x5, 0x0, 0x2, version.as_bytes().to_vec())
+        Attribute::new("OS-Version", 0x5, 0x0, AttributeValue::Text(version.to_string()))
     }
 
     fn os_language(language: &str) -> Attribute {
-        Attribute::new("OS-Lang", 0x6, 0x0, 0x2, language.as_bytes().to_vec())
+        Attribute::new("OS-Lang", 0x6, 0x0, AttributeValue::Text(language.to_string()))
     }
 
     fn cpu_info(cpu_info: &str) -> Attribute {
-        Attribute::new("CPU-Info", 0x8, 0x0, 0x2, cpu_info.as_bytes().to_vec())
+        Attribute::new("CPU-Info", 0x8, 0x0, AttributeValue::Text(cpu_info.to_string()))
     }
 
     fn mac_address(mac_address: &[u8; 4]) -> Attribute {
-        Attribute::new("MAC-Address", 0x9, 0x0, 0x2, mac_address.to_vec())
+        Attribute::new("MAC-Address", 0x9, 0x0, AttributeValue::Bytes(mac_address.to_vec()))
     }
 
     fn memory_size(size: u32) -> Attribute {
-        let size_be = size.to_be();
-        let size_bytes = integer_to_bytes(&size_be);
-        Attribute::new("Memory-Size", 0xa, 0x0, 0x0, size_bytes.to_vec())
+        Attribute::new("Memory-Size", 0xa, 0x0, AttributeValue::Integer(size))
     }
 
     fn default_explorer(explorer: &str) -> Attribute {
         Attribute::new("Default-Explorer",
                        0xb,
                        0x0,
-                       0x2,
-                       explorer.as_bytes().to_vec())
+                       AttributeValue::Text(explorer.to_string()))
     }
 
     fn keepalive_data(data: &str) -> Attribute {
-        Attribute::new("KeepAlive-Data", 0x14, 0x0, 0x2, data.as_bytes().to_vec())
+        Attribute::new("KeepAlive-Data", 0x14, 0x0, AttributeValue::Text(data.to_string()))
     }
 
     fn keepalive_time(timestamp: u32) -> Attribute {
-        let timestamp_be = timestamp.to_be();
-        let timestamp_bytes = integer_to_bytes(&timestamp_be);
-        Attribute::new("KeepAlive-Time", 0x12, 0x0, 0x0, timestamp_bytes.to_vec())
+        Attribute::new("KeepAlive-Time", 0x12, 0x0, AttributeValue::Integer(timestamp))
     }
 
     fn calc_keepalive_data(timestamp: Option<u32>, last_data: Option<&str>) -> String {
         let timenow = match timestamp {
             Some(timestamp) => timestamp,
-            None => current_timestamp(),
+            #[cfg(feature = "std")]
+            None => SystemClock.now(),
+            #[cfg(not(feature = "std"))]
+            None => {
+                panic!("calc_keepalive_data(None, _) requires the `std` feature; pass an \
+                        explicit timestamp from a `Clock` under no_std")
+            }
         };
 
         let salt = match last_data {
@@ -154,20 +391,59 @@ impl AttributeFactory for Attribute {
 
         let keepalive_data;
         {
-            let mut md5 = Hasher::new(Type::MD5).unwrap();
+            let mut md5 = Md5::new();
             let timenow_be = timenow.to_be();
             let timenow_bytes = integer_to_bytes(&timenow_be);
 
-            md5.update(timenow_bytes).unwrap();
-            md5.update(salt.as_bytes()).unwrap();
+            md5.update(&timenow_bytes);
+            md5.update(salt.as_bytes());
 
-            let hashed_bytes = md5.finish().unwrap();
-            keepalive_data = hashed_bytes[..].to_hex();
+            let hashed_bytes = md5.finish();
+            keepalive_data = hex_encode(&hashed_bytes);
         }
         keepalive_data
     }
 }
 
+/// Threads the rolling `KeepAlive-Data` MD5 chain so callers don't have to pass
+/// `last_data`/timestamps by hand on every heartbeat. The chain starts at the
+/// `"llwl"` seed, exactly as `calc_keepalive_data(_, None)` does, and each
+/// `next()` call salts with the previous digest and advances the chain.
+pub struct KeepaliveSession<C: Clock> {
+    chain: String,
+    clock: C,
+}
+
+impl<C: Clock> KeepaliveSession<C> {
+    pub fn new(clock: C) -> Self {
+        KeepaliveSession {
+            chain: "llwl".to_string(),
+            clock: clock,
+        }
+    }
+
+    /// Computes the next `KeepAlive-Data`/`KeepAlive-Time` pair for the current
+    /// clock reading, advancing the chain so the following call salts with
+    /// this digest.
+    pub fn next(&mut self) -> (Attribute, Attribute) {
+        let timestamp = self.clock.now();
+        let data = Attribute::calc_keepalive_data(Some(timestamp), Some(&self.chain));
+
+        let keepalive_data = Attribute::keepalive_data(&data);
+        let keepalive_time = Attribute::keepalive_time(timestamp);
+        self.chain = data;
+
+        (keepalive_data, keepalive_time)
+    }
+}
+
+#[cfg(feature = "std")]
+impl KeepaliveSession<SystemClock> {
+    pub fn with_system_clock() -> Self {
+        KeepaliveSession::new(SystemClock)
+    }
+}
+
 impl AttributeVec for Vec<Attribute> {
     fn as_bytes(&self) -> Vec<u8> {
         let mut attributes_bytes: Vec<u8> = Vec::new();
@@ -176,6 +452,17 @@ impl AttributeVec for Vec<Attribute> {
         }
         attributes_bytes
     }
+
+    fn parse_all(bytes: &[u8]) -> Result<Vec<Attribute>, ParseError> {
+        let mut attributes = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let attribute = Attribute::from_bytes(&bytes[offset..])?;
+            offset += attribute.length() as usize;
+            attributes.push(attribute);
+        }
+        Ok(attributes)
+    }
 }
 
 #[test]
@@ -193,4 +480,60 @@ fn test_keepalive_data() {
                                                   Some("ffb0b2af94693fd1ba4c93e6b9aebd3f"));
     assert_eq!(kp_data1, "ffb0b2af94693fd1ba4c93e6b9aebd3f");
     assert_eq!(kp_data2, "d0dce2b013c8adfac646a2917fdab802");
+}
+
+#[test]
+fn test_attribute_parse_roundtrip() {
+    let attrs = vec![Attribute::username("05802278989@HYXY.XY"),
+                      Attribute::client_ip_address(Ipv4Addr::new(10, 0, 0, 1)),
+                      Attribute::memory_size(1024)];
+    let bytes = attrs.as_bytes();
+    let parsed = Vec::<Attribute>::parse_all(&bytes).unwrap();
+    assert_eq!(parsed, attrs);
+}
+
+#[test]
+fn test_attribute_parse_truncated() {
+    let un = Attribute::username("05802278989@HYXY.XY");
+    let bytes = un.as_bytes();
+    assert_eq!(Attribute::from_bytes(&bytes[..2]), Err(ParseError::Truncated));
+}
+
+#[test]
+fn test_attribute_parse_invalid_utf8() {
+    let bytes: &[u8] = &[0x1, 0x0, 0x5, 0xff, 0xff];
+    assert_eq!(Attribute::from_bytes(bytes), Err(ParseError::InvalidUtf8));
+}
+
+struct FixedClock(u32);
+
+impl Clock for FixedClock {
+    fn now(&self) -> u32 {
+        self.0
+    }
+}
+
+#[test]
+fn test_keepalive_session_advances_chain() {
+    let mut session = KeepaliveSession::new(FixedClock(1472483020));
+
+    let (data1, time1) = session.next();
+    assert_eq!(data1, Attribute::keepalive_data("ffb0b2af94693fd1ba4c93e6b9aebd3f"));
+    assert_eq!(time1, Attribute::keepalive_time(1472483020));
+
+    let (data2, _) = session.next();
+    assert_eq!(data2, Attribute::keepalive_data("d0dce2b013c8adfac646a2917fdab802"));
+}
+
+#[test]
+fn test_attribute_value_round_trips_as_typed_variant() {
+    let memory = Attribute::memory_size(1024);
+    let bytes = memory.as_bytes();
+    let parsed = Attribute::from_bytes(&bytes).unwrap();
+    assert_eq!(parsed, memory);
+
+    let ip = Attribute::client_ip_address(Ipv4Addr::new(192, 168, 1, 1));
+    let bytes = ip.as_bytes();
+    let parsed = Attribute::from_bytes(&bytes).unwrap();
+    assert_eq!(parsed, ip);
 }
\ No newline at end of file